@@ -4,10 +4,13 @@ extern crate url;
 use self::url::Url;
 
 use std::ffi::{CStr, CString};
-use std::io::{stderr, Write};
+use std::io::{self, stderr, Read, Seek, SeekFrom, Write};
 use std::os::raw as libc;
-use std::{ptr, str};
+use std::{mem, panic, ptr, str};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
 
 use result::*;
 use result::Error::DatabaseError;
@@ -15,10 +18,126 @@ use result::Error::DatabaseError;
 #[allow(missing_debug_implementations, missing_copy_implementations)]
 pub struct RawConnection {
     pub internal_connection: *mut ffi::sqlite3,
+    interrupt_pointer: Arc<Mutex<*mut ffi::sqlite3>>,
+    free_commit_hook: Option<unsafe fn(*mut libc::c_void)>,
+    free_rollback_hook: Option<unsafe fn(*mut libc::c_void)>,
+    free_update_hook: Option<unsafe fn(*mut libc::c_void)>,
+    free_busy_handler: Option<unsafe fn(*mut libc::c_void)>,
+    // SQLite does not hand back the previous busy-handler user data, so the
+    // closure pointer is tracked here to be freed when it is replaced.
+    busy_handler_pointer: Option<*mut libc::c_void>,
+    free_trace_hook: Option<unsafe fn(*mut libc::c_void)>,
+    free_profile_hook: Option<unsafe fn(*mut libc::c_void)>,
 }
 
+static LOG_INIT: Once = Once::new();
+static mut LOG_CALLBACK: Option<fn(libc::c_int, &str)> = None;
+
 const BUSY_TIMEOUT: i32 = 5000;
 
+/// The set of flags passed to `sqlite3_open_v2` when opening a connection.
+///
+/// The flags are derived from extra query-string parameters in the connection
+/// URL, e.g. `sqlite:foo.db?mode=ro&cache=shared&vfs=unix-dotfile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags {
+    bits: libc::c_int,
+}
+
+impl OpenFlags {
+    /// The raw flag bits to pass to `sqlite3_open_v2`.
+    pub fn bits(self) -> libc::c_int {
+        self.bits
+    }
+
+    /// Build the open flags from the query-string parameters of a connection
+    /// URL. Unrecognized values for `mode`, `cache`, and `mutex` are reported
+    /// as an invalid connection URL.
+    fn from_query_params(
+        params: &HashMap<Cow<str>, Cow<str>>,
+        database_url: &str,
+    ) -> ConnectionResult<Self> {
+        let invalid = || ConnectionError::InvalidConnectionUrl(database_url.to_owned());
+
+        let mut bits = match params.get("mode").map(|mode| mode.as_ref()) {
+            None => ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+            Some("ro") => ffi::SQLITE_OPEN_READONLY,
+            Some("rw") => ffi::SQLITE_OPEN_READWRITE,
+            Some("rwc") => ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+            Some("memory") => {
+                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE | ffi::SQLITE_OPEN_MEMORY
+            }
+            Some(_) => return Err(invalid()),
+        };
+
+        match params.get("cache").map(|cache| cache.as_ref()) {
+            None => {}
+            Some("shared") => bits |= ffi::SQLITE_OPEN_SHAREDCACHE,
+            Some("private") => bits |= ffi::SQLITE_OPEN_PRIVATECACHE,
+            Some(_) => return Err(invalid()),
+        }
+
+        match params.get("mutex").map(|mutex| mutex.as_ref()) {
+            None => {}
+            Some("no") => bits |= ffi::SQLITE_OPEN_NOMUTEX,
+            Some("full") => bits |= ffi::SQLITE_OPEN_FULLMUTEX,
+            Some(_) => return Err(invalid()),
+        }
+
+        if params.get("uri").map(|uri| uri.as_ref()) == Some("true") {
+            bits |= ffi::SQLITE_OPEN_URI;
+        }
+
+        Ok(OpenFlags { bits })
+    }
+}
+
+/// The kind of row change reported to an update hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Insert,
+    Update,
+    Delete,
+    Unknown(libc::c_int),
+}
+
+/// A dynamically typed SQLite value, as passed to and returned from a custom
+/// SQL function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Double(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// The progress of an in-flight online backup, reported to the optional
+/// callback passed to [`RawConnection::backup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// The number of pages still to be copied.
+    pub remaining: libc::c_int,
+    /// The total number of pages in the source database.
+    pub pagecount: libc::c_int,
+}
+
+struct AggregateUserData<A> {
+    step: Box<dyn FnMut(&mut A, &[Value]) -> QueryResult<()>>,
+    finalize: Box<dyn FnMut(A) -> QueryResult<Value>>,
+}
+
+impl Action {
+    fn from_code(code: libc::c_int) -> Self {
+        match code {
+            ffi::SQLITE_INSERT => Action::Insert,
+            ffi::SQLITE_UPDATE => Action::Update,
+            ffi::SQLITE_DELETE => Action::Delete,
+            other => Action::Unknown(other),
+        }
+    }
+}
+
 impl RawConnection {
     /// Support database_url like sqlite:db.db?key=123
     pub fn establish(database_url: &str) -> ConnectionResult<Self> {
@@ -32,11 +151,22 @@ impl RawConnection {
         let database_url = url.path();
         let params: HashMap<_, _> = url.query_pairs().collect();
         let key = params.get("key");
+        let flags = try!(OpenFlags::from_query_params(&params, database_url));
+        let vfs = match params.get("vfs") {
+            Some(vfs) => Some(try!(CString::new(vfs.to_string()))),
+            None => None,
+        };
+        let vfs_pointer = vfs.as_ref().map(|v| v.as_ptr()).unwrap_or_else(ptr::null);
 
         let mut conn_pointer = ptr::null_mut();
         let database_url = try!(CString::new(database_url));
         let connection_status = unsafe {
-            let mut status_code = ffi::sqlite3_open(database_url.as_ptr(), &mut conn_pointer);
+            let mut status_code = ffi::sqlite3_open_v2(
+                database_url.as_ptr(),
+                &mut conn_pointer,
+                flags.bits(),
+                vfs_pointer,
+            );
             ensure_status_code_ok(status_code)?;
             status_code = ffi::sqlite3_busy_timeout(conn_pointer, BUSY_TIMEOUT);
             if let Some(key) = key {
@@ -50,6 +180,14 @@ impl RawConnection {
         match connection_status {
             ffi::SQLITE_OK => Ok(RawConnection {
                 internal_connection: conn_pointer,
+                interrupt_pointer: Arc::new(Mutex::new(conn_pointer)),
+                free_commit_hook: None,
+                free_rollback_hook: None,
+                free_update_hook: None,
+                free_busy_handler: None,
+                busy_handler_pointer: None,
+                free_trace_hook: None,
+                free_profile_hook: None,
             }),
             err_code => {
                 let message = super::error_message(err_code);
@@ -77,7 +215,7 @@ impl RawConnection {
             Ok(())
         } else {
             let msg = convert_to_string_and_free(err_msg);
-            let error_kind = DatabaseErrorKind::__Unknown;
+            let error_kind = error_kind_from_code(self.last_error_code());
             Err(DatabaseError(error_kind, Box::new(msg)))
         }
     }
@@ -95,6 +233,310 @@ impl RawConnection {
         unsafe { ffi::sqlite3_extended_errcode(self.internal_connection) }
     }
 
+    /// Register a scalar SQL function implemented by a Rust closure.
+    ///
+    /// `n_arg` is the number of arguments the function expects (`-1` for a
+    /// variable number). When `deterministic` is `true` SQLite is told the
+    /// function always returns the same result for the same inputs, allowing
+    /// it to be used in indexes and `WHERE` clauses on virtual tables.
+    pub fn create_scalar_function(
+        &self,
+        name: &str,
+        n_arg: libc::c_int,
+        deterministic: bool,
+        f: Box<dyn FnMut(&[Value]) -> QueryResult<Value>>,
+    ) -> QueryResult<()> {
+        let name = try!(CString::new(name));
+        let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+        let mut flags = ffi::SQLITE_UTF8;
+        if deterministic {
+            flags |= ffi::SQLITE_DETERMINISTIC;
+        }
+        let result = unsafe {
+            ffi::sqlite3_create_function_v2(
+                self.internal_connection,
+                name.as_ptr(),
+                n_arg,
+                flags,
+                callback_pointer,
+                Some(scalar_function_trampoline),
+                None,
+                None,
+                Some(free_boxed_hook::<Box<dyn FnMut(&[Value]) -> QueryResult<Value>>>),
+            )
+        };
+        ensure_sqlite_ok(result, self.internal_connection)
+    }
+
+    /// Register an aggregate SQL function implemented by a `step` and a
+    /// `finalize` closure sharing an accumulator of type `A`.
+    ///
+    /// `step` is invoked once per row with a mutable accumulator (initialized
+    /// with `A::default()` on the first row), and `finalize` consumes the
+    /// accumulator to produce the aggregate result.
+    pub fn create_aggregate_function<A>(
+        &self,
+        name: &str,
+        n_arg: libc::c_int,
+        step: Box<dyn FnMut(&mut A, &[Value]) -> QueryResult<()>>,
+        finalize: Box<dyn FnMut(A) -> QueryResult<Value>>,
+    ) -> QueryResult<()>
+    where
+        A: Default + 'static,
+    {
+        let name = try!(CString::new(name));
+        let user_data = Box::new(AggregateUserData { step, finalize });
+        let callback_pointer = Box::into_raw(user_data) as *mut libc::c_void;
+        let result = unsafe {
+            ffi::sqlite3_create_function_v2(
+                self.internal_connection,
+                name.as_ptr(),
+                n_arg,
+                ffi::SQLITE_UTF8,
+                callback_pointer,
+                None,
+                Some(aggregate_step_trampoline::<A>),
+                Some(aggregate_finalize_trampoline::<A>),
+                Some(free_boxed_hook::<AggregateUserData<A>>),
+            )
+        };
+        ensure_sqlite_ok(result, self.internal_connection)
+    }
+
+    /// Set the amount of time to wait for a locked database before giving up.
+    ///
+    /// This installs SQLite's default busy handler, replacing any handler
+    /// previously registered with [`busy_handler`](RawConnection::busy_handler).
+    pub fn busy_timeout(&mut self, ms: i32) -> QueryResult<()> {
+        self.drop_busy_handler();
+        let status =
+            unsafe { ffi::sqlite3_busy_timeout(self.internal_connection, ms as libc::c_int) };
+        ensure_sqlite_ok(status, self.internal_connection)
+    }
+
+    /// Register a callback invoked when the database is locked.
+    ///
+    /// The callback receives the number of times it has been invoked for the
+    /// current locking event and returns whether SQLite should keep waiting.
+    /// Passing `None` clears any registered handler.
+    pub fn busy_handler(&mut self, f: Option<Box<dyn FnMut(libc::c_int) -> bool>>) -> QueryResult<()> {
+        // Drop any previously installed handler before replacing it.
+        self.drop_busy_handler();
+        let status = match f {
+            Some(f) => {
+                let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+                self.busy_handler_pointer = Some(callback_pointer);
+                self.free_busy_handler = Some(free_boxed_hook::<Box<dyn FnMut(libc::c_int) -> bool>>);
+                unsafe {
+                    ffi::sqlite3_busy_handler(
+                        self.internal_connection,
+                        Some(busy_handler_trampoline),
+                        callback_pointer,
+                    )
+                }
+            }
+            None => unsafe {
+                ffi::sqlite3_busy_handler(self.internal_connection, None, ptr::null_mut())
+            },
+        };
+        ensure_sqlite_ok(status, self.internal_connection)
+    }
+
+    fn drop_busy_handler(&mut self) {
+        // SQLite does not return the previous user data for busy handlers, so
+        // the closure pointer saved when it was installed is dropped here.
+        if let (Some(free), Some(handler)) =
+            (self.free_busy_handler.take(), self.busy_handler_pointer.take())
+        {
+            unsafe { free(handler) };
+        }
+    }
+
+    /// Register a callback invoked with the expanded SQL text of each
+    /// statement as it is run. Passing `None` clears the callback.
+    pub fn trace(&mut self, f: Option<Box<dyn FnMut(&str)>>) {
+        self.drop_trace_hook();
+        match f {
+            Some(f) => {
+                let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+                self.free_trace_hook = Some(free_boxed_hook::<Box<dyn FnMut(&str)>>);
+                unsafe {
+                    ffi::sqlite3_trace(
+                        self.internal_connection,
+                        Some(trace_trampoline),
+                        callback_pointer,
+                    );
+                }
+            }
+            None => unsafe {
+                ffi::sqlite3_trace(self.internal_connection, None, ptr::null_mut());
+            },
+        }
+    }
+
+    /// Register a callback invoked with the SQL text and execution time of
+    /// each statement once it finishes. Passing `None` clears the callback.
+    pub fn profile(&mut self, f: Option<Box<dyn FnMut(&str, Duration)>>) {
+        self.drop_profile_hook();
+        match f {
+            Some(f) => {
+                let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+                self.free_profile_hook = Some(free_boxed_hook::<Box<dyn FnMut(&str, Duration)>>);
+                unsafe {
+                    ffi::sqlite3_profile(
+                        self.internal_connection,
+                        Some(profile_trampoline),
+                        callback_pointer,
+                    );
+                }
+            }
+            None => unsafe {
+                ffi::sqlite3_profile(self.internal_connection, None, ptr::null_mut());
+            },
+        }
+    }
+
+    /// Route SQLite's internal error and warning log through a Rust function.
+    ///
+    /// This installs a process-wide log sink via `SQLITE_CONFIG_LOG` and must
+    /// be called before any connection is opened; it is therefore guarded by a
+    /// `Once` and takes effect only on its first invocation. Passing `None`
+    /// leaves logging disabled.
+    pub fn config_log(f: Option<fn(libc::c_int, &str)>) {
+        LOG_INIT.call_once(|| unsafe {
+            LOG_CALLBACK = f;
+            ffi::sqlite3_config(
+                ffi::SQLITE_CONFIG_LOG,
+                config_log_trampoline as *const libc::c_void,
+                ptr::null_mut::<libc::c_void>(),
+            );
+        });
+    }
+
+    fn drop_trace_hook(&mut self) {
+        if let Some(free) = self.free_trace_hook.take() {
+            let prev = unsafe {
+                ffi::sqlite3_trace(self.internal_connection, None, ptr::null_mut())
+            };
+            if !prev.is_null() {
+                unsafe { free(prev) };
+            }
+        }
+    }
+
+    fn drop_profile_hook(&mut self) {
+        if let Some(free) = self.free_profile_hook.take() {
+            let prev = unsafe {
+                ffi::sqlite3_profile(self.internal_connection, None, ptr::null_mut())
+            };
+            if !prev.is_null() {
+                unsafe { free(prev) };
+            }
+        }
+    }
+
+    /// Open a handle to a single BLOB for incremental I/O.
+    ///
+    /// The returned [`Blob`] implements `Read`, `Write`, and `Seek` so large
+    /// binary columns can be streamed rather than materialized whole. When
+    /// `read_only` is `false` the blob is opened for writing; note that writes
+    /// cannot grow or shrink the blob, so writing past its end is an error.
+    pub fn blob_open(
+        &self,
+        db: &str,
+        table: &str,
+        column: &str,
+        rowid: i64,
+        read_only: bool,
+    ) -> QueryResult<Blob> {
+        let db = try!(CString::new(db));
+        let table = try!(CString::new(table));
+        let column = try!(CString::new(column));
+        let flags = if read_only { 0 } else { 1 };
+        let mut blob_pointer = ptr::null_mut();
+        let status = unsafe {
+            ffi::sqlite3_blob_open(
+                self.internal_connection,
+                db.as_ptr(),
+                table.as_ptr(),
+                column.as_ptr(),
+                rowid,
+                flags,
+                &mut blob_pointer,
+            )
+        };
+        try!(ensure_sqlite_ok(status, self.internal_connection));
+        let len = unsafe { ffi::sqlite3_blob_bytes(blob_pointer) };
+        Ok(Blob {
+            blob: blob_pointer,
+            len,
+            offset: 0,
+        })
+    }
+
+    /// Copy the contents of this database into `dst` incrementally using
+    /// SQLite's online backup API.
+    ///
+    /// `pages_per_step` pages are copied per iteration; pass `-1` to copy the
+    /// entire database in a single step. After each step the optional
+    /// `progress` callback is invoked with the remaining and total page
+    /// counts. The backup retries after a short sleep whenever the source
+    /// database is busy or locked.
+    pub fn backup(
+        &self,
+        dst: &RawConnection,
+        pages_per_step: libc::c_int,
+        mut progress: Option<Box<dyn FnMut(BackupProgress)>>,
+    ) -> QueryResult<()> {
+        let name = try!(CString::new("main"));
+        unsafe {
+            let backup = ffi::sqlite3_backup_init(
+                dst.internal_connection,
+                name.as_ptr(),
+                self.internal_connection,
+                name.as_ptr(),
+            );
+            if backup.is_null() {
+                return ensure_sqlite_ok(
+                    ffi::sqlite3_errcode(dst.internal_connection),
+                    dst.internal_connection,
+                );
+            }
+
+            loop {
+                let status = ffi::sqlite3_backup_step(backup, pages_per_step);
+                if let Some(ref mut progress) = progress {
+                    progress(BackupProgress {
+                        remaining: ffi::sqlite3_backup_remaining(backup),
+                        pagecount: ffi::sqlite3_backup_pagecount(backup),
+                    });
+                }
+                match status {
+                    ffi::SQLITE_OK => {}
+                    ffi::SQLITE_DONE => break,
+                    ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => {
+                        ::std::thread::sleep(::std::time::Duration::from_millis(250));
+                    }
+                    err => {
+                        ffi::sqlite3_backup_finish(backup);
+                        return ensure_sqlite_ok(err, dst.internal_connection);
+                    }
+                }
+            }
+
+            let finish_status = ffi::sqlite3_backup_finish(backup);
+            ensure_sqlite_ok(finish_status, dst.internal_connection)
+        }
+    }
+
+    /// Return a handle that can interrupt a long-running query from another
+    /// thread by calling `sqlite3_interrupt` on this connection.
+    pub fn get_interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            interrupt_pointer: Arc::clone(&self.interrupt_pointer),
+        }
+    }
+
     pub fn rekey(&self, password: &str) -> QueryResult<libc::c_int> {
         let passphrase = try!(CString::new(password));
         let passphrase_len = (password.len() + 1) as libc::c_int;
@@ -102,12 +544,452 @@ impl RawConnection {
             Ok(ffi::sqlite3_rekey(self.internal_connection, passphrase.as_ptr() as *mut libc::c_void, passphrase_len))
         }
     }
+
+    /// Register a callback invoked whenever a transaction is committed.
+    ///
+    /// Returning `true` from the callback turns the commit into a rollback,
+    /// mirroring the return value of `sqlite3_commit_hook`.
+    pub fn set_commit_hook(&mut self, f: Box<dyn FnMut() -> bool>) {
+        self.drop_commit_hook();
+        let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+        unsafe {
+            ffi::sqlite3_commit_hook(
+                self.internal_connection,
+                Some(commit_hook_trampoline),
+                callback_pointer,
+            );
+        }
+        self.free_commit_hook = Some(free_boxed_hook::<Box<dyn FnMut() -> bool>>);
+    }
+
+    /// Register a callback invoked whenever a transaction is rolled back.
+    pub fn set_rollback_hook(&mut self, f: Box<dyn FnMut()>) {
+        self.drop_rollback_hook();
+        let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+        unsafe {
+            ffi::sqlite3_rollback_hook(
+                self.internal_connection,
+                Some(rollback_hook_trampoline),
+                callback_pointer,
+            );
+        }
+        self.free_rollback_hook = Some(free_boxed_hook::<Box<dyn FnMut()>>);
+    }
+
+    /// Register a callback invoked whenever a row is inserted, updated, or
+    /// deleted. The callback receives the operation, database name, table
+    /// name, and the affected rowid.
+    pub fn set_update_hook(&mut self, f: Box<dyn FnMut(Action, &str, &str, i64)>) {
+        self.drop_update_hook();
+        let callback_pointer = Box::into_raw(Box::new(f)) as *mut libc::c_void;
+        unsafe {
+            ffi::sqlite3_update_hook(
+                self.internal_connection,
+                Some(update_hook_trampoline),
+                callback_pointer,
+            );
+        }
+        self.free_update_hook = Some(free_boxed_hook::<Box<dyn FnMut(Action, &str, &str, i64)>>);
+    }
+
+    fn drop_commit_hook(&mut self) {
+        if let Some(free) = self.free_commit_hook.take() {
+            let prev = unsafe {
+                ffi::sqlite3_commit_hook(self.internal_connection, None, ptr::null_mut())
+            };
+            if !prev.is_null() {
+                unsafe { free(prev) };
+            }
+        }
+    }
+
+    fn drop_rollback_hook(&mut self) {
+        if let Some(free) = self.free_rollback_hook.take() {
+            let prev = unsafe {
+                ffi::sqlite3_rollback_hook(self.internal_connection, None, ptr::null_mut())
+            };
+            if !prev.is_null() {
+                unsafe { free(prev) };
+            }
+        }
+    }
+
+    fn drop_update_hook(&mut self) {
+        if let Some(free) = self.free_update_hook.take() {
+            let prev = unsafe {
+                ffi::sqlite3_update_hook(self.internal_connection, None, ptr::null_mut())
+            };
+            if !prev.is_null() {
+                unsafe { free(prev) };
+            }
+        }
+    }
+}
+
+/// A handle to a `RawConnection` that may be used to interrupt an in-progress
+/// query from another thread.
+///
+/// Access to the underlying `sqlite3` pointer is serialized against
+/// `sqlite3_close` via a shared mutex, so it is always safe to call
+/// `interrupt()` even if the originating connection is being dropped.
+#[allow(missing_debug_implementations)]
+pub struct InterruptHandle {
+    interrupt_pointer: Arc<Mutex<*mut ffi::sqlite3>>,
+}
+
+// The raw pointer is only ever dereferenced while holding the mutex, and
+// `sqlite3_interrupt` is safe to call from any thread.
+unsafe impl Send for InterruptHandle {}
+unsafe impl Sync for InterruptHandle {}
+
+impl InterruptHandle {
+    /// Interrupt the currently running query on the associated connection.
+    ///
+    /// Does nothing if the connection has already been closed.
+    pub fn interrupt(&self) {
+        let conn = self.interrupt_pointer.lock().unwrap();
+        if !conn.is_null() {
+            unsafe { ffi::sqlite3_interrupt(*conn) };
+        }
+    }
+}
+
+/// A handle to a single SQLite BLOB, supporting incremental `Read`, `Write`,
+/// and `Seek` against a tracked offset.
+///
+/// The underlying blob is closed when the handle is dropped. Its length is
+/// fixed at open time, so writes may not extend past the end of the blob.
+#[allow(missing_debug_implementations)]
+pub struct Blob {
+    blob: *mut ffi::sqlite3_blob,
+    len: libc::c_int,
+    offset: libc::c_int,
+}
+
+impl Blob {
+    /// The total number of bytes in this blob.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Whether this blob is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for Blob {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = (self.len - self.offset) as usize;
+        let n = ::std::cmp::min(remaining, buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        let status = unsafe {
+            ffi::sqlite3_blob_read(
+                self.blob,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                n as libc::c_int,
+                self.offset,
+            )
+        };
+        if status == ffi::SQLITE_OK {
+            self.offset += n as libc::c_int;
+            Ok(n)
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, "sqlite3_blob_read failed"))
+        }
+    }
+}
+
+impl Write for Blob {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A blob's length is fixed, so a write must fit entirely within the
+        // bytes remaining from the current offset.
+        if self.offset as i64 + buf.len() as i64 > self.len as i64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot write past the end of the blob",
+            ));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let status = unsafe {
+            ffi::sqlite3_blob_write(
+                self.blob,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len() as libc::c_int,
+                self.offset,
+            )
+        };
+        if status == ffi::SQLITE_OK {
+            self.offset += buf.len() as libc::c_int;
+            Ok(buf.len())
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, "sqlite3_blob_write failed"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Blob {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.len as i64 + n,
+            SeekFrom::Current(n) => self.offset as i64 + n,
+        };
+        if new_offset < 0 || new_offset > self.len as i64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek past the bounds of the blob",
+            ));
+        }
+        self.offset = new_offset as libc::c_int;
+        Ok(new_offset as u64)
+    }
+}
+
+impl Drop for Blob {
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_blob_close(self.blob) };
+    }
+}
+
+unsafe fn free_boxed_hook<F>(hook: *mut libc::c_void) {
+    drop(Box::from_raw(hook as *mut F));
+}
+
+fn ensure_sqlite_ok(code: libc::c_int, conn: *mut ffi::sqlite3) -> QueryResult<()> {
+    if code == ffi::SQLITE_OK {
+        Ok(())
+    } else {
+        let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(conn)) }
+            .to_string_lossy()
+            .into_owned();
+        Err(DatabaseError(DatabaseErrorKind::__Unknown, Box::new(message)))
+    }
+}
+
+unsafe fn value_from_raw(value: *mut ffi::sqlite3_value) -> Value {
+    match ffi::sqlite3_value_type(value) {
+        ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_value_int64(value) as i64),
+        ffi::SQLITE_FLOAT => Value::Double(ffi::sqlite3_value_double(value)),
+        ffi::SQLITE_TEXT => {
+            let ptr = ffi::sqlite3_value_text(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            let bytes = ::std::slice::from_raw_parts(ptr as *const u8, len);
+            Value::Text(String::from_utf8_lossy(bytes).into_owned())
+        }
+        ffi::SQLITE_BLOB => {
+            let ptr = ffi::sqlite3_value_blob(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            let bytes = ::std::slice::from_raw_parts(ptr as *const u8, len);
+            Value::Blob(bytes.to_vec())
+        }
+        _ => Value::Null,
+    }
+}
+
+unsafe fn collect_args(argc: libc::c_int, argv: *mut *mut ffi::sqlite3_value) -> Vec<Value> {
+    (0..argc as isize)
+        .map(|i| value_from_raw(*argv.offset(i)))
+        .collect()
+}
+
+unsafe fn set_result(ctx: *mut ffi::sqlite3_context, value: Value) {
+    // SQLite must copy text/blob payloads because they are freed when this
+    // function returns.
+    let transient: ffi::sqlite3_destructor_type =
+        mem::transmute(-1_isize as *mut libc::c_void);
+    match value {
+        Value::Null => ffi::sqlite3_result_null(ctx),
+        Value::Integer(i) => ffi::sqlite3_result_int64(ctx, i),
+        Value::Double(d) => ffi::sqlite3_result_double(ctx, d),
+        Value::Text(s) => {
+            ffi::sqlite3_result_text(
+                ctx,
+                s.as_ptr() as *const libc::c_char,
+                s.len() as libc::c_int,
+                transient,
+            );
+        }
+        Value::Blob(b) => {
+            ffi::sqlite3_result_blob(
+                ctx,
+                b.as_ptr() as *const libc::c_void,
+                b.len() as libc::c_int,
+                transient,
+            );
+        }
+    }
+}
+
+unsafe fn set_error(ctx: *mut ffi::sqlite3_context, message: &str) {
+    match CString::new(message) {
+        Ok(message) => ffi::sqlite3_result_error(ctx, message.as_ptr(), -1),
+        Err(_) => ffi::sqlite3_result_error_code(ctx, ffi::SQLITE_ERROR),
+    }
+}
+
+extern "C" fn scalar_function_trampoline(
+    ctx: *mut ffi::sqlite3_context,
+    argc: libc::c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    unsafe {
+        let callback = ffi::sqlite3_user_data(ctx) as *mut Box<dyn FnMut(&[Value]) -> QueryResult<Value>>;
+        let args = collect_args(argc, argv);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| (*callback)(&args)));
+        match result {
+            Ok(Ok(value)) => set_result(ctx, value),
+            Ok(Err(e)) => set_error(ctx, &e.to_string()),
+            Err(_) => set_error(ctx, "custom function panicked"),
+        }
+    }
+}
+
+unsafe fn aggregate_context<A: Default>(ctx: *mut ffi::sqlite3_context) -> Option<*mut A> {
+    let pp = ffi::sqlite3_aggregate_context(ctx, mem::size_of::<*mut A>() as libc::c_int)
+        as *mut *mut A;
+    if pp.is_null() {
+        return None;
+    }
+    if (*pp).is_null() {
+        *pp = Box::into_raw(Box::new(A::default()));
+    }
+    Some(*pp)
+}
+
+extern "C" fn aggregate_step_trampoline<A: Default + 'static>(
+    ctx: *mut ffi::sqlite3_context,
+    argc: libc::c_int,
+    argv: *mut *mut ffi::sqlite3_value,
+) {
+    unsafe {
+        let user_data = ffi::sqlite3_user_data(ctx) as *mut AggregateUserData<A>;
+        let acc = match aggregate_context::<A>(ctx) {
+            Some(acc) => acc,
+            None => {
+                ffi::sqlite3_result_error_nomem(ctx);
+                return;
+            }
+        };
+        let args = collect_args(argc, argv);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            ((*user_data).step)(&mut *acc, &args)
+        }));
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => set_error(ctx, &e.to_string()),
+            Err(_) => set_error(ctx, "aggregate function panicked"),
+        }
+    }
+}
+
+extern "C" fn aggregate_finalize_trampoline<A: Default + 'static>(ctx: *mut ffi::sqlite3_context) {
+    unsafe {
+        let user_data = ffi::sqlite3_user_data(ctx) as *mut AggregateUserData<A>;
+        let pp = ffi::sqlite3_aggregate_context(ctx, 0) as *mut *mut A;
+        let acc = if pp.is_null() || (*pp).is_null() {
+            A::default()
+        } else {
+            *Box::from_raw(*pp)
+        };
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| ((*user_data).finalize)(acc)));
+        match result {
+            Ok(Ok(value)) => set_result(ctx, value),
+            Ok(Err(e)) => set_error(ctx, &e.to_string()),
+            Err(_) => set_error(ctx, "aggregate function panicked"),
+        }
+    }
+}
+
+extern "C" fn commit_hook_trampoline(user_data: *mut libc::c_void) -> libc::c_int {
+    let callback = unsafe { &mut *(user_data as *mut Box<dyn FnMut() -> bool>) };
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| callback()));
+    // A panic aborts the commit (non-zero return) rather than unwinding into C.
+    result.unwrap_or(true) as libc::c_int
+}
+
+extern "C" fn rollback_hook_trampoline(user_data: *mut libc::c_void) {
+    let callback = unsafe { &mut *(user_data as *mut Box<dyn FnMut()>) };
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| callback()));
+}
+
+extern "C" fn busy_handler_trampoline(
+    user_data: *mut libc::c_void,
+    count: libc::c_int,
+) -> libc::c_int {
+    let callback = unsafe { &mut *(user_data as *mut Box<dyn FnMut(libc::c_int) -> bool>) };
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| callback(count)));
+    // A panic stops waiting (return 0) rather than unwinding into C.
+    result.unwrap_or(false) as libc::c_int
+}
+
+extern "C" fn trace_trampoline(user_data: *mut libc::c_void, sql: *const libc::c_char) {
+    let callback = unsafe { &mut *(user_data as *mut Box<dyn FnMut(&str)>) };
+    let sql = unsafe { CStr::from_ptr(sql) }.to_string_lossy();
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| callback(&sql)));
+}
+
+extern "C" fn profile_trampoline(
+    user_data: *mut libc::c_void,
+    sql: *const libc::c_char,
+    nanoseconds: ffi::sqlite3_uint64,
+) {
+    let callback = unsafe { &mut *(user_data as *mut Box<dyn FnMut(&str, Duration)>) };
+    let sql = unsafe { CStr::from_ptr(sql) }.to_string_lossy();
+    let duration = Duration::new(nanoseconds / 1_000_000_000, (nanoseconds % 1_000_000_000) as u32);
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| callback(&sql, duration)));
+}
+
+extern "C" fn config_log_trampoline(
+    _user_data: *mut libc::c_void,
+    err_code: libc::c_int,
+    message: *const libc::c_char,
+) {
+    if let Some(callback) = unsafe { LOG_CALLBACK } {
+        let message = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| callback(err_code, &message)));
+    }
+}
+
+extern "C" fn update_hook_trampoline(
+    user_data: *mut libc::c_void,
+    action: libc::c_int,
+    database_name: *const libc::c_char,
+    table_name: *const libc::c_char,
+    rowid: ffi::sqlite3_int64,
+) {
+    let callback = unsafe { &mut *(user_data as *mut Box<dyn FnMut(Action, &str, &str, i64)>) };
+    let database_name = unsafe { CStr::from_ptr(database_name) }.to_string_lossy();
+    let table_name = unsafe { CStr::from_ptr(table_name) }.to_string_lossy();
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        callback(Action::from_code(action), &database_name, &table_name, rowid as i64)
+    }));
 }
 
 impl Drop for RawConnection {
     fn drop(&mut self) {
         use std::thread::panicking;
 
+        self.drop_commit_hook();
+        self.drop_rollback_hook();
+        self.drop_update_hook();
+        self.drop_busy_handler();
+        self.drop_trace_hook();
+        self.drop_profile_hook();
+
+        // Null out the interrupt copy of the pointer while holding the mutex so
+        // a concurrent `InterruptHandle::interrupt` cannot race with the close.
+        let mut conn = self.interrupt_pointer.lock().unwrap_or_else(|e| e.into_inner());
+        *conn = ptr::null_mut();
+
         let close_result = unsafe { ffi::sqlite3_close(self.internal_connection) };
         if close_result != ffi::SQLITE_OK {
             let error_message = super::error_message(close_result);
@@ -133,6 +1015,23 @@ fn convert_to_string_and_free(err_msg: *const libc::c_char) -> String {
     msg
 }
 
+/// Translate a SQLite primary or extended result code into the matching
+/// `DatabaseErrorKind`.
+///
+/// Constraint violations that Diesel models explicitly are mapped to their
+/// dedicated kinds; everything else falls back to `__Unknown`.
+fn error_kind_from_code(code: libc::c_int) -> DatabaseErrorKind {
+    match code {
+        ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {
+            DatabaseErrorKind::UniqueViolation
+        }
+        ffi::SQLITE_CONSTRAINT_FOREIGNKEY => DatabaseErrorKind::ForeignKeyViolation,
+        // Diesel has no dedicated kind for NOT NULL / CHECK violations on this
+        // backend yet, so they are reported as unknown for now.
+        _ => DatabaseErrorKind::__Unknown,
+    }
+}
+
 fn ensure_status_code_ok(status_code: libc::c_int) -> ConnectionResult<()> {
     match status_code {
         ffi::SQLITE_OK => Ok(()),